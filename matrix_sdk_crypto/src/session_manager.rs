@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use dashmap::{DashMap, DashSet};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use matrix_sdk_common::{
     api::r0::{
         keys::claim_keys::{Request as KeysClaimRequest, Response as KeysClaimResponse},
@@ -22,10 +26,11 @@ use matrix_sdk_common::{
     },
     assign,
     events::EventType,
-    identifiers::{DeviceId, DeviceIdBox, DeviceKeyAlgorithm, UserId},
+    identifiers::{DeviceId, DeviceIdBox, DeviceKeyAlgorithm, ServerName, UserId},
     uuid::Uuid,
 };
 use serde_json::{json, value::to_raw_value};
+use tokio::sync::broadcast;
 use tracing::{error, info, warn};
 
 use crate::{
@@ -37,6 +42,75 @@ use crate::{
     Device,
 };
 
+/// Bookkeeping for a server that failed to answer a `/keys/claim` request for
+/// one or more of its devices.
+///
+/// Used to back off further claims to that server for a while instead of
+/// hammering it with requests that are likely to fail again.
+#[derive(Debug, Clone)]
+struct FailureEntry {
+    /// The number of consecutive key-claim failures we've seen for this
+    /// server.
+    failure_count: u32,
+    /// The earliest time at which we should try claiming keys from this
+    /// server again.
+    next_retry: Instant,
+}
+
+impl FailureEntry {
+    /// The maximum amount of time we'll back off a server for.
+    const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+    fn new() -> Self {
+        let mut entry = Self { failure_count: 0, next_retry: Instant::now() };
+        entry.record_failure();
+        entry
+    }
+
+    /// Record another failure, bumping the backoff with capped exponential
+    /// growth.
+    fn record_failure(&mut self) {
+        self.failure_count += 1;
+        let backoff = Duration::from_secs(2u64.saturating_pow(self.failure_count));
+        self.next_retry = Instant::now() + backoff.min(Self::MAX_BACKOFF);
+    }
+
+    fn is_backed_off(&self) -> bool {
+        Instant::now() < self.next_retry
+    }
+}
+
+/// Configuration for the policies that [`SessionManager`] applies, such as
+/// how often a persistently wedged device may be re-unwedged.
+#[derive(Debug, Clone)]
+pub(crate) struct SessionManagerConfig {
+    /// The minimum amount of time that has to pass between two attempts to
+    /// unwedge the same device, so a flapping device doesn't cause a storm
+    /// of dummy `m.room_encrypted` messages.
+    pub unwedging_interval: Duration,
+}
+
+impl Default for SessionManagerConfig {
+    fn default() -> Self {
+        Self { unwedging_interval: Duration::from_secs(60 * 60) }
+    }
+}
+
+/// Events emitted by the [`SessionManager`] as it establishes Olm sessions,
+/// useful for e.g. UI layers that want to surface "establishing secure
+/// session..." state without relying on store side effects.
+#[derive(Debug, Clone)]
+pub(crate) enum SessionManagerEvent {
+    /// A new Olm session was successfully created with a device.
+    SessionCreated { user_id: UserId, device_id: DeviceIdBox },
+    /// A key-claim request to a server failed.
+    KeyClaimFailed { server: Box<ServerName>, reason: String },
+    /// A device was marked as wedged and is waiting to be unwedged.
+    DeviceWedged { user_id: UserId, device_id: DeviceIdBox },
+    /// A dummy message was queued up for a wedged device to unwedge it.
+    DeviceUnwedged { user_id: UserId, device_id: DeviceIdBox },
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct SessionManager {
     account: Account,
@@ -46,21 +120,44 @@ pub(crate) struct SessionManager {
     /// user/device paris will be added to the list of users when
     /// [`get_missing_sessions`](#method.get_missing_sessions) is called.
     users_for_key_claim: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
-    wedged_devices: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
+    /// Devices that we consider wedged, together with the last time we tried
+    /// to send them a dummy message to unwedge them, if any.
+    wedged_devices: Arc<DashMap<UserId, DashMap<DeviceIdBox, Option<Instant>>>>,
     key_request_machine: KeyRequestMachine,
     outgoing_to_device_requests: Arc<DashMap<Uuid, OutgoingRequest>>,
+    /// Servers that recently failed to answer a key-claim request, kept
+    /// around so we can back off further claims against them for a while.
+    server_failures: Arc<DashMap<Box<ServerName>, FailureEntry>>,
+    /// The maximum number of devices we'll put into a single key-claim
+    /// request, so we don't send out requests that some homeservers might
+    /// reject or process slowly.
+    max_devices_per_claim: usize,
+    config: SessionManagerConfig,
+    /// Sender half of the channel used to notify observers of
+    /// session-establishment progress, see [`subscribe`](#method.subscribe).
+    event_sender: broadcast::Sender<SessionManagerEvent>,
 }
 
 impl SessionManager {
     const KEY_CLAIM_TIMEOUT: Duration = Duration::from_secs(10);
-    const UNWEDGING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+    /// The default value for [`max_devices_per_claim`](#structfield.max_devices_per_claim).
+    const DEFAULT_MAX_DEVICES_PER_CLAIM: usize = 250;
+    /// The capacity of the broadcast channel used for [`subscribe`](#method.subscribe).
+    const EVENT_CHANNEL_CAPACITY: usize = 100;
+    /// How many multiples of [`unwedging_interval`](SessionManagerConfig#structfield.unwedging_interval)
+    /// a `wedged_devices` entry is allowed to sit untouched before
+    /// [`sweep_stale_wedged_devices`](#method.sweep_stale_wedged_devices) evicts it.
+    const WEDGED_ENTRY_SWEEP_MULTIPLIER: u32 = 4;
 
     pub fn new(
         account: Account,
         users_for_key_claim: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
         key_request_machine: KeyRequestMachine,
         store: Store,
+        config: SessionManagerConfig,
     ) -> Self {
+        let (event_sender, _) = broadcast::channel(Self::EVENT_CHANNEL_CAPACITY);
+
         Self {
             account,
             store,
@@ -68,9 +165,36 @@ impl SessionManager {
             users_for_key_claim,
             wedged_devices: Arc::new(DashMap::new()),
             outgoing_to_device_requests: Arc::new(DashMap::new()),
+            server_failures: Arc::new(DashMap::new()),
+            max_devices_per_claim: Self::DEFAULT_MAX_DEVICES_PER_CLAIM,
+            config,
+            event_sender,
         }
     }
 
+    /// Subscribe to [`SessionManagerEvent`]s emitted while sessions are being
+    /// established.
+    ///
+    /// This lets UI layers surface "establishing secure session..." state and
+    /// lets tests assert behavior without relying on store side effects.
+    #[allow(dead_code)]
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionManagerEvent> {
+        self.event_sender.subscribe()
+    }
+
+    /// Get the set of servers that are currently being backed off from due to
+    /// recent key-claim failures.
+    ///
+    /// Exposed for debugging purposes.
+    #[allow(dead_code)]
+    pub fn backed_off_servers(&self) -> Vec<Box<ServerName>> {
+        self.server_failures
+            .iter()
+            .filter(|e| e.is_backed_off())
+            .map(|e| e.key().clone())
+            .collect()
+    }
+
     /// Mark the outgoing request as sent.
     pub fn mark_outgoing_request_as_sent(&self, id: &Uuid) {
         self.outgoing_to_device_requests.remove(id);
@@ -82,6 +206,8 @@ impl SessionManager {
             .get_device_from_curve_key(sender, curve_key)
             .await?
         {
+            self.sweep_stale_wedged_devices(device.user_id());
+
             let sessions = device.get_sessions().await?;
 
             if let Some(sessions) = sessions {
@@ -91,11 +217,20 @@ impl SessionManager {
                 let session = sessions.get(0);
 
                 if let Some(session) = session {
-                    if session.creation_time.elapsed() > Self::UNWEDGING_INTERVAL {
-                        self.wedged_devices
+                    if session.creation_time.elapsed() > self.config.unwedging_interval {
+                        if let Entry::Vacant(entry) = self
+                            .wedged_devices
                             .entry(device.user_id().to_owned())
-                            .or_insert_with(DashSet::new)
-                            .insert(device.device_id().into());
+                            .or_insert_with(DashMap::new)
+                            .entry(device.device_id().into())
+                        {
+                            entry.insert(None);
+
+                            let _ = self.event_sender.send(SessionManagerEvent::DeviceWedged {
+                                user_id: device.user_id().to_owned(),
+                                device_id: device.device_id().into(),
+                            });
+                        }
                     }
                 }
             }
@@ -104,25 +239,73 @@ impl SessionManager {
         Ok(())
     }
 
+    /// Evict bookkeeping entries for `user_id` whose last unwedge attempt is
+    /// long past the configured
+    /// [`unwedging_interval`](SessionManagerConfig#structfield.unwedging_interval),
+    /// so `wedged_devices` doesn't grow forever for devices that recovered
+    /// and were never wedged again. Entries that haven't had an unwedge
+    /// attempt yet (`None`) are left alone, since they're still pending.
+    fn sweep_stale_wedged_devices(&self, user_id: &UserId) {
+        if let Some(devices) = self.wedged_devices.get(user_id) {
+            let stale_after = self.config.unwedging_interval * Self::WEDGED_ENTRY_SWEEP_MULTIPLIER;
+            let now = Instant::now();
+
+            devices.retain(|_, last_attempt| {
+                last_attempt.map(|t| now.duration_since(t) < stale_after).unwrap_or(true)
+            });
+        }
+    }
+
+    /// Check whether a device still has a pending unwedging attempt.
+    ///
+    /// Returns `false` once a dummy message has been queued up for the device, even
+    /// though we keep a bookkeeping entry around for a while afterwards to
+    /// rate-limit further attempts, see [`check_if_unwedged`].
+    ///
+    /// [`check_if_unwedged`]: #method.check_if_unwedged
     #[allow(dead_code)]
     pub fn is_device_wedged(&self, device: &Device) -> bool {
         self.wedged_devices
             .get(device.user_id())
-            .map(|d| d.contains(device.device_id()))
+            .and_then(|d| d.get(device.device_id()).map(|a| a.is_none()))
             .unwrap_or(false)
     }
 
     /// Check if the session was created to unwedge a Device.
     ///
-    /// If the device was wedged this will queue up a dummy to-device message.
+    /// If the device was wedged and hasn't had a dummy message queued up for
+    /// it within the configured
+    /// [`unwedging_interval`](SessionManagerConfig#structfield.unwedging_interval),
+    /// this will queue up a dummy to-device message and record the attempt,
+    /// so a flapping device doesn't cause a storm of dummy messages.
+    ///
+    /// The bookkeeping entry is kept around after a successful unwedge to
+    /// enforce that cooldown; it's cleaned up opportunistically by
+    /// [`sweep_stale_wedged_devices`] once it's old enough that the device is
+    /// clearly no longer flapping.
+    ///
+    /// [`sweep_stale_wedged_devices`]: #method.sweep_stale_wedged_devices
     async fn check_if_unwedged(&self, user_id: &UserId, device_id: &DeviceId) -> OlmResult<()> {
-        if self
-            .wedged_devices
-            .get(user_id)
-            .map(|d| d.remove(device_id))
-            .flatten()
-            .is_some()
-        {
+        let should_unwedge = if let Some(devices) = self.wedged_devices.get(user_id) {
+            if let Some(mut last_attempt) = devices.get_mut(device_id) {
+                let now = Instant::now();
+                let should_unwedge = last_attempt
+                    .map(|t| now.duration_since(t) >= self.config.unwedging_interval)
+                    .unwrap_or(true);
+
+                if should_unwedge {
+                    *last_attempt = Some(now);
+                }
+
+                should_unwedge
+            } else {
+                false
+            }
+        } else {
+            false
+        };
+
+        if should_unwedge {
             if let Some(device) = self.store.get_device(user_id, device_id).await? {
                 let content = device.encrypt(EventType::Dummy, json!({})).await?;
                 let id = Uuid::new_v4();
@@ -149,12 +332,26 @@ impl SessionManager {
                 };
 
                 self.outgoing_to_device_requests.insert(id, request);
+
+                let _ = self.event_sender.send(SessionManagerEvent::DeviceUnwedged {
+                    user_id: device.user_id().to_owned(),
+                    device_id: device.device_id().into(),
+                });
             }
         }
 
         Ok(())
     }
 
+    /// Check whether `server` is currently being backed off due to recent
+    /// key-claim failures.
+    fn is_server_backed_off(&self, server: &ServerName) -> bool {
+        self.server_failures
+            .get(server)
+            .map(|e| e.is_backed_off())
+            .unwrap_or(false)
+    }
+
     /// Get the a key claiming request for the user/device pairs that we are
     /// missing Olm sessions for.
     ///
@@ -172,6 +369,12 @@ impl SessionManager {
     /// **Note**: Care should be taken that only one such request at a time is
     /// in flight, e.g. using a lock.
     ///
+    /// At most [`max_devices_per_claim`](#structfield.max_devices_per_claim)
+    /// devices are put into a single request, devices that are already
+    /// queued up in [`users_for_key_claim`](#structfield.users_for_key_claim)
+    /// take priority. Any devices that don't fit are picked up on a
+    /// subsequent call.
+    ///
     /// The response of a successful key claiming requests needs to be passed to
     /// the `OlmMachine` with the [`receive_keys_claim_response`].
     ///
@@ -187,13 +390,70 @@ impl SessionManager {
         users: &mut impl Iterator<Item = &UserId>,
     ) -> OlmResult<Option<(Uuid, KeysClaimRequest)>> {
         let mut missing = BTreeMap::new();
+        let mut device_count = 0;
+
+        // Add the list of sessions that for some reason automatically need to
+        // create an Olm session first, they take priority over the ones
+        // below since other submodules are blocked on them being created.
+        //
+        // users_for_key_claim is a DashMap/DashSet pair, whose iteration order
+        // isn't stable, so sort both levels by id first. Without this, which
+        // devices get dropped once max_devices_per_claim is hit would depend
+        // on hash map iteration order instead of being deterministic.
+        let mut priority_users: Vec<UserId> = self
+            .users_for_key_claim
+            .iter()
+            .map(|item| item.key().to_owned())
+            .collect();
+        priority_users.sort();
+
+        for user in &priority_users {
+            if self.is_server_backed_off(user.server_name()) {
+                continue;
+            }
+
+            if let Some(devices) = self.users_for_key_claim.get(user) {
+                let mut device_ids: Vec<DeviceIdBox> =
+                    devices.iter().map(|d| d.to_owned()).collect();
+                device_ids.sort();
+
+                for device_id in device_ids {
+                    if device_count >= self.max_devices_per_claim {
+                        break;
+                    }
+
+                    if missing
+                        .entry(user.to_owned())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(device_id, DeviceKeyAlgorithm::SignedCurve25519)
+                        .is_none()
+                    {
+                        device_count += 1;
+                    }
+                }
+            }
+        }
 
         // Add the list of devices that the user wishes to establish sessions
-        // right now.
+        // right now, filling up the remaining space in this claim.
         for user_id in users {
+            if device_count >= self.max_devices_per_claim {
+                break;
+            }
+
+            if self.is_server_backed_off(user_id.server_name()) {
+                continue;
+            }
+
             let user_devices = self.store.get_user_devices(user_id).await?;
+            let mut devices: Vec<_> = user_devices.devices().collect();
+            devices.sort_by(|a, b| a.device_id().cmp(b.device_id()));
+
+            for device in devices {
+                if device_count >= self.max_devices_per_claim {
+                    break;
+                }
 
-            for device in user_devices.devices() {
                 let sender_key = if let Some(k) = device.get_key(DeviceKeyAlgorithm::Curve25519) {
                     k
                 } else {
@@ -209,30 +469,19 @@ impl SessionManager {
                 };
 
                 if is_missing {
-                    missing
-                        .entry(user_id.to_owned())
-                        .or_insert_with(BTreeMap::new)
-                        .insert(
+                    let devices = missing.entry(user_id.to_owned()).or_insert_with(BTreeMap::new);
+
+                    if !devices.contains_key(device.device_id()) {
+                        devices.insert(
                             device.device_id().into(),
                             DeviceKeyAlgorithm::SignedCurve25519,
                         );
+                        device_count += 1;
+                    }
                 }
             }
         }
 
-        // Add the list of sessions that for some reason automatically need to
-        // create an Olm session.
-        for item in self.users_for_key_claim.iter() {
-            let user = item.key();
-
-            for device_id in item.value().iter() {
-                missing
-                    .entry(user.to_owned())
-                    .or_insert_with(BTreeMap::new)
-                    .insert(device_id.to_owned(), DeviceKeyAlgorithm::SignedCurve25519);
-            }
-        }
-
         if missing.is_empty() {
             Ok(None)
         } else {
@@ -252,9 +501,32 @@ impl SessionManager {
     ///
     /// * `response` - The response containing the claimed one-time keys.
     pub async fn receive_keys_claim_response(&self, response: &KeysClaimResponse) -> OlmResult<()> {
-        // TODO log the failures here
+        for (server, reason) in &response.failures {
+            warn!(
+                "Key claiming request to server {} failed: {:?}, backing off",
+                server, reason
+            );
+
+            self.server_failures
+                .entry(server.clone())
+                .and_modify(|e| e.record_failure())
+                .or_insert_with(FailureEntry::new);
+
+            let _ = self.event_sender.send(SessionManagerEvent::KeyClaimFailed {
+                server: server.clone(),
+                reason: format!("{:?}", reason),
+            });
+        }
 
         for (user_id, user_devices) in &response.one_time_keys {
+            // Don't let a success for this user undo the backoff we just
+            // recorded above for their server in this very response; the two
+            // maps are independent, so a single response could in principle
+            // carry both a failure and a success for the same server.
+            if !response.failures.contains_key(user_id.server_name()) {
+                self.server_failures.remove(user_id.server_name());
+            }
+
             for (device_id, key_map) in user_devices {
                 let device = match self.store.get_readonly_device(&user_id, device_id).await {
                     Ok(Some(d)) => d,
@@ -290,6 +562,11 @@ impl SessionManager {
                     continue;
                 }
 
+                let _ = self.event_sender.send(SessionManagerEvent::SessionCreated {
+                    user_id: user_id.to_owned(),
+                    device_id: device_id.to_owned(),
+                });
+
                 self.key_request_machine.retry_keyshare(&user_id, device_id);
 
                 if let Err(e) = self.check_if_unwedged(&user_id, device_id).await {
@@ -306,16 +583,21 @@ impl SessionManager {
 
 #[cfg(test)]
 mod test {
-    use dashmap::DashMap;
-    use std::{collections::BTreeMap, sync::Arc};
+    use dashmap::{DashMap, DashSet};
+    use std::{
+        collections::BTreeMap,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
 
     use matrix_sdk_common::{
         api::r0::keys::claim_keys::Response as KeyClaimResponse,
         identifiers::{user_id, DeviceIdBox, UserId},
     };
     use matrix_sdk_test::async_test;
+    use serde_json::json;
 
-    use super::SessionManager;
+    use super::{SessionManager, SessionManagerConfig, SessionManagerEvent};
     use crate::{
         identities::ReadOnlyDevice,
         key_request::KeyRequestMachine,
@@ -366,7 +648,13 @@ mod test {
             users_for_key_claim.clone(),
         );
 
-        SessionManager::new(account, users_for_key_claim, key_request, store)
+        SessionManager::new(
+            account,
+            users_for_key_claim,
+            key_request,
+            store,
+            SessionManagerConfig::default(),
+        )
     }
 
     #[async_test]
@@ -412,4 +700,370 @@ mod test {
             .unwrap()
             .is_none());
     }
+
+    #[async_test]
+    async fn key_claim_failure_backs_off_server() {
+        let manager = session_manager().await;
+        let bob = bob_account();
+
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        manager.store.save_devices(&[bob_device]).await.unwrap();
+
+        let mut failures = BTreeMap::new();
+        failures.insert(bob.user_id().server_name().to_owned(), json!({}));
+
+        let response = KeyClaimResponse {
+            failures,
+            one_time_keys: BTreeMap::new(),
+        };
+
+        manager
+            .receive_keys_claim_response(&response)
+            .await
+            .unwrap();
+
+        assert!(manager
+            .backed_off_servers()
+            .contains(&bob.user_id().server_name().to_owned()));
+
+        assert!(manager
+            .get_missing_sessions(&mut [bob.user_id().clone()].iter())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[async_test]
+    async fn a_success_does_not_undo_a_failure_for_the_same_server_in_one_response() {
+        let manager = session_manager().await;
+        let bob = bob_account();
+
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        manager.store.save_devices(&[bob_device]).await.unwrap();
+
+        bob.generate_one_time_keys_helper(1).await;
+        let one_time = bob.signed_one_time_keys_helper().await.unwrap();
+        bob.mark_keys_as_published().await;
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys
+            .entry(bob.user_id().clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(bob.device_id().into(), one_time);
+
+        let mut failures = BTreeMap::new();
+        failures.insert(bob.user_id().server_name().to_owned(), json!({}));
+
+        // A single response carrying both a failure and a success for the
+        // same server isn't ruled out by the type system, even if real
+        // homeservers are unlikely to produce one.
+        let response = KeyClaimResponse { failures, one_time_keys };
+
+        manager
+            .receive_keys_claim_response(&response)
+            .await
+            .unwrap();
+
+        assert!(
+            manager
+                .backed_off_servers()
+                .contains(&bob.user_id().server_name().to_owned()),
+            "The backoff recorded for a failing server shouldn't be undone by a \
+            success for the same server in the same response"
+        );
+    }
+
+    #[async_test]
+    async fn get_missing_sessions_respects_the_device_limit() {
+        let mut manager = session_manager().await;
+        manager.max_devices_per_claim = 1;
+
+        let bob_1 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE1".into());
+        let bob_2 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE2".into());
+
+        let bob_device_1 = ReadOnlyDevice::from_account(&bob_1).await;
+        let bob_device_2 = ReadOnlyDevice::from_account(&bob_2).await;
+
+        manager
+            .store
+            .save_devices(&[bob_device_1, bob_device_2])
+            .await
+            .unwrap();
+
+        let (_, request) = manager
+            .get_missing_sessions(&mut [bob_1.user_id().clone()].iter())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let devices = request.one_time_keys.get(bob_1.user_id()).unwrap();
+        assert_eq!(devices.len(), 1);
+    }
+
+    #[async_test]
+    async fn get_missing_sessions_prioritizes_users_for_key_claim() {
+        let mut manager = session_manager().await;
+        manager.max_devices_per_claim = 1;
+
+        let bob_1 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE1".into());
+        let bob_2 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE2".into());
+
+        let bob_device_1 = ReadOnlyDevice::from_account(&bob_1).await;
+        let bob_device_2 = ReadOnlyDevice::from_account(&bob_2).await;
+
+        manager
+            .store
+            .save_devices(&[bob_device_1, bob_device_2])
+            .await
+            .unwrap();
+
+        // DEVICE2 is already queued up for an automatic key claim, it should
+        // win the single available slot over DEVICE1, which is only found
+        // because it's missing a session for the user we pass in.
+        manager
+            .users_for_key_claim
+            .entry(bob_2.user_id().to_owned())
+            .or_insert_with(DashSet::new)
+            .insert(bob_2.device_id().into());
+
+        let (_, request) = manager
+            .get_missing_sessions(&mut [bob_1.user_id().clone()].iter())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let devices = request.one_time_keys.get(bob_1.user_id()).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert!(
+            devices.contains_key(bob_2.device_id()),
+            "The device queued in users_for_key_claim should take priority over \
+            one merely passed in via `users`"
+        );
+    }
+
+    #[async_test]
+    async fn get_missing_sessions_selects_devices_deterministically() {
+        let mut manager = session_manager().await;
+        manager.max_devices_per_claim = 2;
+
+        let bob_1 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE1".into());
+        let bob_2 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE2".into());
+        let bob_3 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE3".into());
+
+        let bob_device_1 = ReadOnlyDevice::from_account(&bob_1).await;
+        let bob_device_2 = ReadOnlyDevice::from_account(&bob_2).await;
+        let bob_device_3 = ReadOnlyDevice::from_account(&bob_3).await;
+
+        manager
+            .store
+            .save_devices(&[bob_device_1, bob_device_2, bob_device_3])
+            .await
+            .unwrap();
+
+        for bob in [&bob_1, &bob_2, &bob_3] {
+            manager
+                .users_for_key_claim
+                .entry(bob.user_id().to_owned())
+                .or_insert_with(DashSet::new)
+                .insert(bob.device_id().into());
+        }
+
+        let no_users: Vec<UserId> = Vec::new();
+        let (_, request) = manager
+            .get_missing_sessions(&mut no_users.iter())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let devices = request.one_time_keys.get(bob_1.user_id()).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert!(
+            devices.contains_key(bob_1.device_id()) && devices.contains_key(bob_2.device_id()),
+            "With a device limit below the candidate count, the lowest-sorting device ids \
+            should always be picked, regardless of DashMap/DashSet iteration order"
+        );
+    }
+
+    #[async_test]
+    async fn get_missing_sessions_selects_fallback_devices_deterministically() {
+        let mut manager = session_manager().await;
+        manager.max_devices_per_claim = 2;
+
+        let bob_1 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE1".into());
+        let bob_2 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE2".into());
+        let bob_3 = ReadOnlyAccount::new(&user_id!("@bob:localhost"), "DEVICE3".into());
+
+        let bob_device_1 = ReadOnlyDevice::from_account(&bob_1).await;
+        let bob_device_2 = ReadOnlyDevice::from_account(&bob_2).await;
+        let bob_device_3 = ReadOnlyDevice::from_account(&bob_3).await;
+
+        manager
+            .store
+            .save_devices(&[bob_device_1, bob_device_2, bob_device_3])
+            .await
+            .unwrap();
+
+        let (_, request) = manager
+            .get_missing_sessions(&mut [bob_1.user_id().clone()].iter())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let devices = request.one_time_keys.get(bob_1.user_id()).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert!(
+            devices.contains_key(bob_1.device_id()) && devices.contains_key(bob_2.device_id()),
+            "The fallback category, filled from the user's devices, should also pick \
+            the lowest-sorting device ids first"
+        );
+    }
+
+    async fn claim_one_time_key_for(manager: &SessionManager, bob: &ReadOnlyAccount) {
+        manager
+            .get_missing_sessions(&mut [bob.user_id().clone()].iter())
+            .await
+            .unwrap();
+
+        bob.generate_one_time_keys_helper(1).await;
+        let one_time = bob.signed_one_time_keys_helper().await.unwrap();
+        bob.mark_keys_as_published().await;
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys
+            .entry(bob.user_id().clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(bob.device_id().into(), one_time);
+
+        let response = KeyClaimResponse { failures: BTreeMap::new(), one_time_keys };
+
+        manager
+            .receive_keys_claim_response(&response)
+            .await
+            .unwrap();
+    }
+
+    #[async_test]
+    async fn unwedging_a_device_stops_reporting_it_as_wedged() {
+        let manager = session_manager().await;
+        let bob = bob_account();
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        manager.store.save_devices(&[bob_device]).await.unwrap();
+
+        claim_one_time_key_for(&manager, &bob).await;
+
+        manager
+            .wedged_devices
+            .entry(bob.user_id().to_owned())
+            .or_insert_with(DashMap::new)
+            .insert(bob.device_id().into(), None);
+
+        let device = manager
+            .store
+            .get_device(bob.user_id(), bob.device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(manager.is_device_wedged(&device), "a pending wedge should be reported");
+
+        claim_one_time_key_for(&manager, &bob).await;
+        assert_eq!(manager.outgoing_to_device_requests.len(), 1);
+
+        assert!(
+            !manager.is_device_wedged(&device),
+            "a device should stop being reported as wedged once it's been unwedged"
+        );
+
+        // Sending another claim for the now-unwedged device shouldn't queue a
+        // second dummy message, since its cooldown hasn't elapsed yet.
+        claim_one_time_key_for(&manager, &bob).await;
+        assert_eq!(manager.outgoing_to_device_requests.len(), 1);
+    }
+
+    #[async_test]
+    async fn check_if_unwedged_respects_the_unwedging_cooldown() {
+        let manager = session_manager().await;
+        let bob = bob_account();
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        manager.store.save_devices(&[bob_device]).await.unwrap();
+
+        manager
+            .wedged_devices
+            .entry(bob.user_id().to_owned())
+            .or_insert_with(DashMap::new)
+            .insert(bob.device_id().into(), Some(Instant::now()));
+
+        manager
+            .check_if_unwedged(bob.user_id(), bob.device_id())
+            .await
+            .unwrap();
+
+        assert!(manager.outgoing_to_device_requests.is_empty());
+        assert!(
+            manager
+                .wedged_devices
+                .get(bob.user_id())
+                .unwrap()
+                .contains_key(bob.device_id()),
+            "The device should still be tracked as wedged while its cooldown hasn't elapsed"
+        );
+    }
+
+    #[async_test]
+    async fn stale_wedged_devices_are_swept() {
+        let mut manager = session_manager().await;
+        manager.config = SessionManagerConfig { unwedging_interval: Duration::from_millis(5) };
+        let bob = bob_account();
+
+        manager
+            .wedged_devices
+            .entry(bob.user_id().to_owned())
+            .or_insert_with(DashMap::new)
+            .insert(bob.device_id().into(), Some(Instant::now()));
+
+        manager.sweep_stale_wedged_devices(bob.user_id());
+        assert!(
+            manager
+                .wedged_devices
+                .get(bob.user_id())
+                .unwrap()
+                .contains_key(bob.device_id()),
+            "a cooldown entry shouldn't be swept before it's older than the sweep threshold"
+        );
+
+        tokio::time::sleep(
+            Duration::from_millis(5) * SessionManager::WEDGED_ENTRY_SWEEP_MULTIPLIER,
+        )
+        .await;
+
+        manager.sweep_stale_wedged_devices(bob.user_id());
+        assert!(
+            !manager
+                .wedged_devices
+                .get(bob.user_id())
+                .unwrap()
+                .contains_key(bob.device_id()),
+            "a cooldown entry should be evicted once it's past the sweep threshold, \
+            so a device that recovered doesn't linger in wedged_devices forever"
+        );
+    }
+
+    #[async_test]
+    async fn session_creation_emits_an_event() {
+        let manager = session_manager().await;
+        let bob = bob_account();
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        manager.store.save_devices(&[bob_device]).await.unwrap();
+
+        let mut events = manager.subscribe();
+
+        claim_one_time_key_for(&manager, &bob).await;
+
+        match events.try_recv().unwrap() {
+            SessionManagerEvent::SessionCreated { user_id, device_id } => {
+                assert_eq!(&user_id, bob.user_id());
+                assert_eq!(device_id.as_str(), bob.device_id().as_str());
+            }
+            e => panic!("Expected a SessionCreated event, got {:?}", e),
+        }
+    }
 }